@@ -134,6 +134,27 @@ impl Method {
     }
 }
 
+/// An interceptor that observes and can influence RPC method dispatch.
+///
+/// Interceptors form an ordered chain around `Dispatcher::dispatch_fallible`: each `before` runs
+/// (in registration order) after the RPC kind has been checked but before the method handler is
+/// invoked, and each `after` runs (in reverse registration order) once the handler has returned
+/// a response successfully. No `after` runs when a `before` or the handler returns an error. This
+/// gives operators a place to add metrics, structured request logging, access control or rate
+/// limiting without touching individual method implementations.
+pub trait RpcInterceptor {
+    /// Called before the method handler is invoked.
+    ///
+    /// If this returns an error, the handler is skipped and the error is turned into a
+    /// `Body::Error` response, just like an error returned by the handler itself.
+    fn before(&self, method: &str, kind: Kind, ctx: &mut Context) -> Result<()>;
+
+    /// Called after the method handler has produced a response.
+    ///
+    /// Not called when a `before` or the handler returns an error.
+    fn after(&self, method: &str, ctx: &mut Context, response: &Response);
+}
+
 /// Key manager policy update handler callback.
 pub type KeyManagerPolicyHandler = dyn Fn(SignedPolicySGX) + Send + Sync;
 /// Key manager quote policy update handler callback.
@@ -150,6 +171,8 @@ pub struct Dispatcher {
     km_quote_policy_handler: Option<Box<KeyManagerQuotePolicyHandler>>,
     /// Registered context initializer.
     ctx_initializer: Option<Box<dyn ContextInitializer + Send + Sync>>,
+    /// Registered interceptor chain, in registration order.
+    interceptors: Vec<Box<dyn RpcInterceptor + Send + Sync>>,
 }
 
 impl Dispatcher {
@@ -158,6 +181,17 @@ impl Dispatcher {
         self.methods.insert(method.get_name().clone(), method);
     }
 
+    /// Register a new interceptor in the dispatcher.
+    ///
+    /// Interceptors are run in registration order for `before` and reverse registration order
+    /// for `after`.
+    pub fn add_interceptor<I>(&mut self, interceptor: I)
+    where
+        I: RpcInterceptor + Send + Sync + 'static,
+    {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
     /// Configure context initializer.
     pub fn set_context_initializer<I>(&mut self, initializer: I)
     where
@@ -186,6 +220,7 @@ impl Dispatcher {
         request: Request,
         kind: Kind,
     ) -> Result<Response> {
+        let method_name = request.method.clone();
         let method = match self.methods.get(&request.method) {
             Some(method) => method,
             None => bail!(DispatchError::MethodNotFound {
@@ -204,7 +239,17 @@ impl Dispatcher {
             }),
         };
 
-        method.dispatch(request, ctx)
+        for interceptor in &self.interceptors {
+            interceptor.before(&method_name, kind, ctx)?;
+        }
+
+        let response = method.dispatch(request, ctx)?;
+
+        for interceptor in self.interceptors.iter().rev() {
+            interceptor.after(&method_name, ctx, &response);
+        }
+
+        Ok(response)
     }
 
     /// Handle key manager policy update.
@@ -1,15 +1,19 @@
 //! Consensus SGX and quote policy handling.
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::{bail, Result};
 use io_context::Context;
-use slog::{debug, Logger};
+use slog::{debug, warn, Logger};
 use thiserror::Error;
 
 use crate::{
     common::{logger::get_logger, namespace::Namespace, sgx::QuotePolicy, version::Version},
     consensus::{
+        beacon::EpochTime,
         keymanager::SignedPolicySGX,
         registry::{SGXConstraints, TEEHardware},
         state::{
@@ -19,8 +23,57 @@ use crate::{
         verifier::Verifier,
         HEIGHT_LATEST,
     },
+    enclave_rpc::{
+        context::Context as RpcContext,
+        dispatcher::{Dispatcher, Method, MethodDescriptor},
+        types::Kind,
+    },
 };
 
+/// Request for a `PolicyVerifier` RPC method.
+///
+/// The same shape is reused across all registered methods: `runtime_id` names the runtime for
+/// `policy.QuotePolicy` and `policy.KeyManager` lookups, and the key manager's own runtime for
+/// `policy.KeyManagerPolicy` lookups.
+#[derive(Clone, Debug, Default, cbor::Encode, cbor::Decode)]
+pub struct PolicyRequest {
+    /// Runtime (or key manager) identifier.
+    pub runtime_id: Namespace,
+    /// Runtime version, used only by `policy.QuotePolicy`.
+    pub version: Option<Version>,
+    /// Whether to use the latest verified consensus layer state.
+    pub use_latest_state: bool,
+}
+
+/// Epoch-scoped cache of resolved policies, so that repeated calls within the same epoch don't
+/// need to re-verify consensus state and re-walk the registry.
+#[derive(Default)]
+struct PolicyCache {
+    /// Most recently observed epoch, from any previous call that verified consensus state.
+    ///
+    /// Reused to serve calls that don't request the absolute latest state (`use_latest_state ==
+    /// false`) without touching `consensus_verifier` at all, since such calls are happy with any
+    /// already-verified view and the epoch can only move forward.
+    last_epoch: Option<EpochTime>,
+    quote_policies: HashMap<(Namespace, Option<Version>), (EpochTime, QuotePolicy)>,
+    km_policies: HashMap<Namespace, (EpochTime, SignedPolicySGX)>,
+    key_managers: HashMap<Namespace, (EpochTime, Namespace)>,
+}
+
+impl PolicyCache {
+    /// Look up `key` in `map`, returning the cached value only if it was cached for exactly
+    /// `epoch`. A cached value for any other epoch is treated as a miss, since the epoch can
+    /// only move forward and a stale value must be re-verified.
+    fn lookup<K, V>(map: &HashMap<K, (EpochTime, V)>, key: &K, epoch: EpochTime) -> Option<V>
+    where
+        K: std::hash::Hash + Eq,
+        V: Clone,
+    {
+        let (cached_epoch, cached_value) = map.get(key)?;
+        (*cached_epoch == epoch).then(|| cached_value.clone())
+    }
+}
+
 /// Policy verifier error.
 #[derive(Error, Debug)]
 pub enum PolicyVerifierError {
@@ -38,20 +91,103 @@ pub enum PolicyVerifierError {
     NoKeyManager,
 }
 
+/// Return the indices, in `valid_from` order, of the deployments whose validity window covers
+/// `epoch`.
+///
+/// `valid_from` must already be sorted in ascending order. A deployment is active once its own
+/// `valid_from` has passed and stays active up to and including the epoch at which the *next*
+/// deployment becomes valid, so that both the old and new deployments are considered active
+/// during the transition epoch.
+fn active_deployment_indices(valid_from: &[EpochTime], epoch: EpochTime) -> Vec<usize> {
+    let mut indices = Vec::new();
+    for (i, &vf) in valid_from.iter().enumerate() {
+        if vf > epoch {
+            continue;
+        }
+        if let Some(&next) = valid_from.get(i + 1) {
+            if epoch > next {
+                continue;
+            }
+        }
+        indices.push(i);
+    }
+    indices
+}
+
 /// Consensus policy verifier.
 pub struct PolicyVerifier {
     consensus_verifier: Arc<dyn Verifier>,
     logger: Logger,
+    /// Whether strict policy verification should be bypassed in favor of a logged warning.
+    ///
+    /// This is only ever true when the `debug-mock-sgx` feature is enabled AND one of the
+    /// `OASIS_UNSAFE_*` environment variables is set, so it can never be silently enabled in a
+    /// production build.
+    unsafe_mock_sgx: bool,
+    /// Epoch-scoped cache of resolved policies, present only when caching was opted into at
+    /// construction time.
+    cache: Option<Mutex<PolicyCache>>,
 }
 
 impl PolicyVerifier {
     /// Create a new consensus policy verifier.
-    pub fn new(consensus_verifier: Arc<dyn Verifier>) -> Self {
+    ///
+    /// If `cache` is true, resolved quote/key manager policies are cached for the epoch that
+    /// produced them, so repeated lookups within the same epoch don't re-verify consensus state.
+    /// This is opt-in because callers that always pass explicit heights (rather than
+    /// `use_latest_state`) may not expect results to be served from a cache.
+    pub fn new(consensus_verifier: Arc<dyn Verifier>, cache: bool) -> Self {
         let logger = get_logger("runtime/policy_verifier");
         Self {
             consensus_verifier,
             logger,
+            unsafe_mock_sgx: Self::unsafe_mock_sgx_enabled(),
+            cache: cache.then(|| Mutex::new(PolicyCache::default())),
+        }
+    }
+
+    /// Clear all cached policies.
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            cache.last_epoch = None;
+            cache.quote_policies.clear();
+            cache.km_policies.clear();
+            cache.key_managers.clear();
+        }
+    }
+
+    /// Return an already-known epoch that is safe to serve a cache hit against without touching
+    /// `consensus_verifier`.
+    ///
+    /// Calls that request the absolute latest state (`use_latest_state == true`) always need a
+    /// fresh verified epoch, since serving one of those from the cache could mean never noticing
+    /// that the epoch has since advanced.
+    fn known_epoch(&self, use_latest_state: bool) -> Option<EpochTime> {
+        if use_latest_state {
+            return None;
         }
+        let cache = self.cache.as_ref()?;
+        cache.lock().unwrap().last_epoch
+    }
+
+    /// Whether any of the unsafe mock SGX environment variables are set.
+    ///
+    /// Gated behind the `debug-mock-sgx` feature so that enabling mock mode is a build-time
+    /// decision, not something an attacker can flip by setting an environment variable on a
+    /// production binary.
+    #[cfg(feature = "debug-mock-sgx")]
+    fn unsafe_mock_sgx_enabled() -> bool {
+        use std::env;
+
+        env::var("OASIS_UNSAFE_MOCK_SGX").is_ok()
+            || env::var("OASIS_UNSAFE_SKIP_AVR_VERIFY").is_ok()
+            || env::var("OASIS_UNSAFE_ALLOW_DEBUG_ENCLAVES").is_ok()
+    }
+
+    #[cfg(not(feature = "debug-mock-sgx"))]
+    fn unsafe_mock_sgx_enabled() -> bool {
+        false
     }
 
     /// Fetch runtime's quote policy from the latest verified consensus layer state.
@@ -64,6 +200,20 @@ impl PolicyVerifier {
         version: Option<Version>,
         use_latest_state: bool,
     ) -> Result<QuotePolicy> {
+        let cache_key = (*runtime_id, version);
+
+        // Fast path: if we already know a usable epoch, try to serve the cache hit without
+        // touching the verifier at all.
+        if let Some(epoch) = self.known_epoch(use_latest_state) {
+            if let Some(cache) = &self.cache {
+                let cache = cache.lock().unwrap();
+                if let Some(policy) = PolicyCache::lookup(&cache.quote_policies, &cache_key, epoch)
+                {
+                    return Ok(policy);
+                }
+            }
+        }
+
         // Verify to the latest height, if needed.
         let consensus_state = if use_latest_state {
             self.consensus_verifier.latest_state()?
@@ -71,6 +221,17 @@ impl PolicyVerifier {
             self.consensus_verifier.state_at(HEIGHT_LATEST)?
         };
 
+        let beacon_state = BeaconState::new(&consensus_state);
+        let epoch = beacon_state.epoch(Context::create_child(&ctx))?;
+
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            cache.last_epoch = Some(epoch);
+            if let Some(policy) = PolicyCache::lookup(&cache.quote_policies, &cache_key, epoch) {
+                return Ok(policy);
+            }
+        }
+
         // Fetch quote policy from the consensus layer using the given or the active version.
         let registry_state = RegistryState::new(&consensus_state);
         let runtime = registry_state
@@ -81,14 +242,9 @@ impl PolicyVerifier {
             Some(version) => runtime
                 .deployment_for_version(version)
                 .ok_or(PolicyVerifierError::NoDeployment)?,
-            None => {
-                let beacon_state = BeaconState::new(&consensus_state);
-                let epoch = beacon_state.epoch(Context::create_child(&ctx))?;
-
-                runtime
-                    .active_deployment(epoch)
-                    .ok_or(PolicyVerifierError::NoDeployment)?
-            }
+            None => runtime
+                .active_deployment(epoch)
+                .ok_or(PolicyVerifierError::NoDeployment)?,
         };
 
         let policy = match runtime.tee_hardware {
@@ -101,9 +257,69 @@ impl PolicyVerifier {
             _ => bail!(PolicyVerifierError::HardwareMismatch),
         };
 
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            cache
+                .quote_policies
+                .insert(cache_key, (epoch, policy.clone()));
+        }
+
         Ok(policy)
     }
 
+    /// Fetch quote policies for all runtime deployments whose validity window covers the
+    /// current epoch.
+    ///
+    /// During a runtime upgrade the old and new deployments can both be valid for a short
+    /// window (until the old one is retired), so more than one policy may be returned.
+    pub fn quote_policies(
+        &self,
+        ctx: Arc<Context>,
+        runtime_id: &Namespace,
+        use_latest_state: bool,
+    ) -> Result<Vec<(Version, QuotePolicy)>> {
+        // Verify to the latest height, if needed.
+        let consensus_state = if use_latest_state {
+            self.consensus_verifier.latest_state()?
+        } else {
+            self.consensus_verifier.state_at(HEIGHT_LATEST)?
+        };
+
+        let registry_state = RegistryState::new(&consensus_state);
+        let runtime = registry_state
+            .runtime(Context::create_child(&ctx), runtime_id)?
+            .ok_or(PolicyVerifierError::MissingRuntimeDescriptor)?;
+
+        let beacon_state = BeaconState::new(&consensus_state);
+        let epoch = beacon_state.epoch(Context::create_child(&ctx))?;
+
+        let mut deployments = runtime.deployments.clone();
+        deployments.sort_by_key(|ad| ad.valid_from);
+
+        let valid_from: Vec<EpochTime> = deployments.iter().map(|ad| ad.valid_from).collect();
+
+        let mut policies = Vec::new();
+        for i in active_deployment_indices(&valid_from, epoch) {
+            let ad = &deployments[i];
+            let policy = match runtime.tee_hardware {
+                TEEHardware::TEEHardwareIntelSGX => {
+                    let sc: SGXConstraints = ad
+                        .try_decode_tee()
+                        .map_err(|_| PolicyVerifierError::BadTEEConstraints)?;
+                    sc.policy()
+                }
+                _ => bail!(PolicyVerifierError::HardwareMismatch),
+            };
+            policies.push((ad.version, policy));
+        }
+
+        if policies.is_empty() {
+            bail!(PolicyVerifierError::NoDeployment);
+        }
+
+        Ok(policies)
+    }
+
     /// Verify that runtime's quote policy has been published in the consensus layer.
     pub fn verify_quote_policy(
         &self,
@@ -116,6 +332,16 @@ impl PolicyVerifier {
         let published_policy = self.quote_policy(ctx, runtime_id, version, use_latest_state)?;
 
         if policy != published_policy {
+            if self.unsafe_mock_sgx {
+                warn!(
+                    self.logger,
+                    "UNSAFE: ignoring quote policy mismatch due to mock SGX mode";
+                    "untrusted" => ?policy,
+                    "published" => ?published_policy,
+                );
+                return Ok(published_policy);
+            }
+
             debug!(
                 self.logger,
                 "quote policy mismatch";
@@ -128,6 +354,52 @@ impl PolicyVerifier {
         Ok(published_policy)
     }
 
+    /// Verify that runtime's quote policy has been published in the consensus layer, accepting
+    /// any of the policies valid for the current epoch.
+    ///
+    /// Unlike [`Self::verify_quote_policy`], this doesn't resolve a single deployment, so it
+    /// also accepts quotes from a deployment that is about to be retired during an upgrade.
+    pub fn verify_quote_policy_any(
+        &self,
+        ctx: Arc<Context>,
+        policy: QuotePolicy,
+        runtime_id: &Namespace,
+        use_latest_state: bool,
+    ) -> Result<QuotePolicy> {
+        let published_policies = self.quote_policies(ctx, runtime_id, use_latest_state)?;
+
+        if let Some((_, published_policy)) =
+            published_policies.iter().find(|(_, p)| *p == policy)
+        {
+            return Ok(published_policy.clone());
+        }
+
+        if self.unsafe_mock_sgx {
+            // Same contract as `verify_quote_policy`: even in mock mode, return a policy we
+            // actually fetched from the consensus layer rather than echoing back the untrusted
+            // input, so callers that treat the `Ok` result as "the verified policy" don't get
+            // unvalidated data back.
+            let (_, published_policy) = published_policies
+                .first()
+                .expect("quote_policies returns at least one policy or an error");
+            warn!(
+                self.logger,
+                "UNSAFE: ignoring quote policy mismatch due to mock SGX mode";
+                "untrusted" => ?policy,
+                "published" => ?published_policies,
+            );
+            return Ok(published_policy.clone());
+        }
+
+        debug!(
+            self.logger,
+            "quote policy mismatch against all published policies";
+            "untrusted" => ?policy,
+            "published" => ?published_policies,
+        );
+        Err(PolicyVerifierError::PolicyNotPublished.into())
+    }
+
     /// Fetch key manager's policy from the latest verified consensus layer state.
     pub fn key_manager_policy(
         &self,
@@ -135,6 +407,18 @@ impl PolicyVerifier {
         key_manager: Namespace,
         use_latest_state: bool,
     ) -> Result<SignedPolicySGX> {
+        // Fast path: if we already know a usable epoch, try to serve the cache hit without
+        // touching the verifier at all.
+        if let Some(epoch) = self.known_epoch(use_latest_state) {
+            if let Some(cache) = &self.cache {
+                let cache = cache.lock().unwrap();
+                if let Some(policy) = PolicyCache::lookup(&cache.km_policies, &key_manager, epoch)
+                {
+                    return Ok(policy);
+                }
+            }
+        }
+
         // Verify to the latest height, if needed.
         let consensus_state = if use_latest_state {
             self.consensus_verifier.latest_state()?
@@ -142,6 +426,17 @@ impl PolicyVerifier {
             self.consensus_verifier.state_at(HEIGHT_LATEST)?
         };
 
+        let beacon_state = BeaconState::new(&consensus_state);
+        let epoch = beacon_state.epoch(Context::create_child(&ctx))?;
+
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            cache.last_epoch = Some(epoch);
+            if let Some(policy) = PolicyCache::lookup(&cache.km_policies, &key_manager, epoch) {
+                return Ok(policy);
+            }
+        }
+
         // Fetch policy from the consensus layer.
         let km_state = KeyManagerState::new(&consensus_state);
         let policy = km_state
@@ -150,6 +445,13 @@ impl PolicyVerifier {
             .policy
             .ok_or(PolicyVerifierError::PolicyNotPublished)?;
 
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            cache
+                .km_policies
+                .insert(key_manager, (epoch, policy.clone()));
+        }
+
         Ok(policy)
     }
 
@@ -164,6 +466,16 @@ impl PolicyVerifier {
         let published_policy = self.key_manager_policy(ctx, key_manager, use_latest_state)?;
 
         if policy != published_policy {
+            if self.unsafe_mock_sgx {
+                warn!(
+                    self.logger,
+                    "UNSAFE: ignoring key manager policy mismatch due to mock SGX mode";
+                    "untrusted" => ?policy,
+                    "published" => ?published_policy,
+                );
+                return Ok(published_policy);
+            }
+
             debug!(
                 self.logger,
                 "key manager policy mismatch";
@@ -183,12 +495,34 @@ impl PolicyVerifier {
         runtime_id: &Namespace,
         use_latest_state: bool,
     ) -> Result<Namespace> {
+        // Fast path: if we already know a usable epoch, try to serve the cache hit without
+        // touching the verifier at all.
+        if let Some(epoch) = self.known_epoch(use_latest_state) {
+            if let Some(cache) = &self.cache {
+                let cache = cache.lock().unwrap();
+                if let Some(km) = PolicyCache::lookup(&cache.key_managers, runtime_id, epoch) {
+                    return Ok(km);
+                }
+            }
+        }
+
         let consensus_state = if use_latest_state {
             self.consensus_verifier.latest_state()?
         } else {
             self.consensus_verifier.state_at(HEIGHT_LATEST)?
         };
 
+        let beacon_state = BeaconState::new(&consensus_state);
+        let epoch = beacon_state.epoch(Context::create_child(&ctx))?;
+
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            cache.last_epoch = Some(epoch);
+            if let Some(km) = PolicyCache::lookup(&cache.key_managers, runtime_id, epoch) {
+                return Ok(km);
+            }
+        }
+
         let registry_state = RegistryState::new(&consensus_state);
         let runtime = registry_state
             .runtime(Context::create_child(&ctx), runtime_id)?
@@ -197,6 +531,111 @@ impl PolicyVerifier {
             .key_manager
             .ok_or(PolicyVerifierError::NoKeyManager)?;
 
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock().unwrap();
+            cache
+                .key_managers
+                .insert(*runtime_id, (epoch, key_manager));
+        }
+
         Ok(key_manager)
     }
+
+    /// Register the verifier's read operations as built-in local-query RPC methods.
+    ///
+    /// This registers `policy.QuotePolicy`, `policy.KeyManagerPolicy` and `policy.KeyManager` on
+    /// the given dispatcher as `Kind::LocalQuery` methods, so every runtime gets a consistent,
+    /// attested way to expose policy lookups without hand-writing the `Method`/`MethodHandler`
+    /// boilerplate.
+    ///
+    /// Takes `&Arc<Self>` rather than `&self` because each registered method is a `'static`
+    /// closure dispatched later, possibly from another thread, so it needs to hold its own owned
+    /// reference to the verifier rather than borrowing one tied to this call's lifetime. Callers
+    /// therefore need to keep their `PolicyVerifier` behind an `Arc` if they want to use this.
+    pub fn register_rpc_methods(self: &Arc<Self>, dispatcher: &mut Dispatcher) {
+        let verifier = self.clone();
+        dispatcher.add_method(Method::new(
+            MethodDescriptor {
+                name: "policy.QuotePolicy".to_string(),
+                kind: Kind::LocalQuery,
+            },
+            move |rq: &PolicyRequest, ctx: &mut RpcContext| {
+                verifier.quote_policy(
+                    ctx.io_ctx.clone(),
+                    &rq.runtime_id,
+                    rq.version,
+                    rq.use_latest_state,
+                )
+            },
+        ));
+
+        let verifier = self.clone();
+        dispatcher.add_method(Method::new(
+            MethodDescriptor {
+                name: "policy.KeyManagerPolicy".to_string(),
+                kind: Kind::LocalQuery,
+            },
+            move |rq: &PolicyRequest, ctx: &mut RpcContext| {
+                verifier.key_manager_policy(ctx.io_ctx.clone(), rq.runtime_id, rq.use_latest_state)
+            },
+        ));
+
+        let verifier = self.clone();
+        dispatcher.add_method(Method::new(
+            MethodDescriptor {
+                name: "policy.KeyManager".to_string(),
+                kind: Kind::LocalQuery,
+            },
+            move |rq: &PolicyRequest, ctx: &mut RpcContext| {
+                verifier.key_manager(ctx.io_ctx.clone(), &rq.runtime_id, rq.use_latest_state)
+            },
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_deployment_indices_upgrade_window() {
+        let valid_from: Vec<EpochTime> = vec![0, 10];
+
+        // Before the new deployment's `valid_from`, only the old one is active.
+        assert_eq!(active_deployment_indices(&valid_from, 9), vec![0]);
+
+        // At the new deployment's `valid_from` epoch, both deployments are still active: the
+        // transition epoch is where the old deployment is retired.
+        assert_eq!(active_deployment_indices(&valid_from, 10), vec![0, 1]);
+
+        // Past the transition epoch, only the new deployment is active.
+        assert_eq!(active_deployment_indices(&valid_from, 11), vec![1]);
+    }
+
+    #[test]
+    fn test_active_deployment_indices_before_first_deployment() {
+        let valid_from: Vec<EpochTime> = vec![5];
+
+        assert_eq!(active_deployment_indices(&valid_from, 4), Vec::<usize>::new());
+        assert_eq!(active_deployment_indices(&valid_from, 5), vec![0]);
+    }
+
+    #[test]
+    fn test_policy_cache_lookup_hit_and_epoch_rollover() {
+        let mut map = HashMap::new();
+        map.insert("runtime-a", (10u64, "policy-at-epoch-10".to_string()));
+
+        // A lookup at the epoch the value was cached for is a hit.
+        assert_eq!(
+            PolicyCache::lookup(&map, &"runtime-a", 10),
+            Some("policy-at-epoch-10".to_string())
+        );
+
+        // Once the epoch has rolled over, the stale entry is a miss, not a hit: the caller is
+        // expected to re-verify and refresh the cache rather than serve a stale policy.
+        assert_eq!(PolicyCache::lookup(&map, &"runtime-a", 11), None);
+
+        // A key that was never cached is also a miss.
+        assert_eq!(PolicyCache::lookup(&map, &"runtime-b", 10), None);
+    }
 }